@@ -27,6 +27,8 @@ use std::usize;
 use Expr;
 use Error;
 use Result;
+use literal::LiteralSet;
+use prefilter::Prefilter;
 
 #[derive(Debug)]
 pub struct AnalyzedExpr<'a> {
@@ -35,6 +37,7 @@ pub struct AnalyzedExpr<'a> {
     pub start_group: usize,
     pub end_group: usize,
     pub min_size: usize,
+    pub max_size: Option<usize>,
     pub const_size: bool,
     pub hard: bool,
     pub looks_left: bool,
@@ -63,6 +66,146 @@ impl<'a> AnalyzedExpr<'a> {
             _ => panic!("push_literal called on non-literal")
         }
     }
+
+    /// The set of literal strings every match of this node is required to
+    /// *start* with, or an empty set if none could be determined. Used to
+    /// build a prefilter for the unanchored search loop.
+    pub fn prefix_literals(&self) -> LiteralSet {
+        match *self.expr {
+            Expr::Literal { casei, ref val } if !casei => LiteralSet::single(val.clone()),
+            Expr::Concat(_) => {
+                let mut buf = String::new();
+                for child in &self.children {
+                    if child.is_literal() && child.const_size {
+                        child.push_literal(&mut buf);
+                    } else {
+                        break;
+                    }
+                }
+                LiteralSet::single(buf)
+            }
+            Expr::Alt(_) => {
+                let mut children = self.children.iter();
+                let first = match children.next() {
+                    Some(child) => child.prefix_literals(),
+                    None => return LiteralSet::empty(),
+                };
+                children.fold(first, |set, child| {
+                    if set.is_empty() {
+                        set
+                    } else {
+                        set.union(child.prefix_literals())
+                    }
+                })
+            }
+            Expr::Repeat { lo, .. } if lo >= 1 => self.children[0].prefix_literals(),
+            Expr::Group(_) | Expr::AtomicGroup(_) => self.children[0].prefix_literals(),
+            _ => LiteralSet::empty(),
+        }
+    }
+
+    /// The set of literal strings every match of this node is required to
+    /// *end* with, or an empty set if none could be determined. Symmetric
+    /// to `prefix_literals`, built from the tail instead of the head.
+    pub fn suffix_literals(&self) -> LiteralSet {
+        match *self.expr {
+            Expr::Literal { casei, ref val } if !casei => LiteralSet::single(val.clone()),
+            Expr::Concat(_) => {
+                let mut literal_tail = Vec::new();
+                for child in self.children.iter().rev() {
+                    if child.is_literal() && child.const_size {
+                        literal_tail.push(child);
+                    } else {
+                        break;
+                    }
+                }
+                let mut buf = String::new();
+                for child in literal_tail.iter().rev() {
+                    child.push_literal(&mut buf);
+                }
+                LiteralSet::single(buf)
+            }
+            Expr::Alt(_) => {
+                let mut children = self.children.iter();
+                let first = match children.next() {
+                    Some(child) => child.suffix_literals(),
+                    None => return LiteralSet::empty(),
+                };
+                children.fold(first, |set, child| {
+                    if set.is_empty() {
+                        set
+                    } else {
+                        set.union(child.suffix_literals())
+                    }
+                })
+            }
+            Expr::Repeat { lo, .. } if lo >= 1 => self.children[0].suffix_literals(),
+            Expr::Group(_) => self.children[0].suffix_literals(),
+            // an atomic group is always `hard`, so it gives no suffix
+            _ => LiteralSet::empty(),
+        }
+    }
+
+    /// A literal string guaranteed to appear *somewhere* in any match of
+    /// this node, even when there's no fixed prefix.
+    pub fn inner_literal(&self) -> Option<String> {
+        match *self.expr {
+            Expr::Literal { casei, ref val } if !casei => Some(val.clone()),
+            Expr::Concat(_) => {
+                // each char of a source literal is its own child node, so
+                // accumulate consecutive literal/const_size runs and keep
+                // the longest one
+                let mut longest = String::new();
+                let mut current = String::new();
+                for child in &self.children {
+                    if child.is_literal() && child.const_size {
+                        child.push_literal(&mut current);
+                    } else {
+                        if current.len() > longest.len() {
+                            longest = current;
+                        }
+                        current = String::new();
+                    }
+                }
+                if current.len() > longest.len() {
+                    longest = current;
+                }
+                if longest.is_empty() {
+                    None
+                } else {
+                    Some(longest)
+                }
+            }
+            Expr::Repeat { lo, .. } if lo >= 1 => self.children[0].inner_literal(),
+            Expr::Group(_) | Expr::AtomicGroup(_) => self.children[0].inner_literal(),
+            _ => None,
+        }
+    }
+
+    /// The `(min, max)` length bounds of any match of this node, in
+    /// characters.
+    /// `max` is `None` when there's no finite upper bound (an unbounded
+    /// repeat or a backref, whose length depends on what was captured).
+    pub fn match_length_bounds(&self) -> (usize, Option<usize>) {
+        (self.min_size, self.max_size)
+    }
+
+    /// Builds the prefilter the unanchored search loop should use for this
+    /// node. Only a required prefix is eligible, since `inner_literal` can
+    /// sit anywhere inside the match and so can't drive a start offset.
+    pub fn build_prefilter(&self) -> Prefilter {
+        Prefilter::from_literal_set(&self.prefix_literals())
+    }
+
+    /// Builds the prefilter a reverse, end-anchored search should use: the
+    /// required suffix literal, if this node has one. Doesn't gate on
+    /// `self.hard`, since that covers the whole expression and a hard
+    /// construct earlier on (e.g. a leading lookaround) doesn't stop the
+    /// tail from having a clean literal suffix; `suffix_literals` already
+    /// excludes a hard node in the tail itself.
+    pub fn build_reverse_prefilter(&self) -> Prefilter {
+        Prefilter::from_literal_set(&self.suffix_literals())
+    }
 }
 
 struct Analyzer<'a> {
@@ -75,6 +218,7 @@ impl<'a> Analyzer<'a> {
         let start_group = self.group_ix;
         let mut children = Vec::new();
         let mut min_size = 0;
+        let mut max_size = Some(0);
         let mut const_size = false;
         let mut hard = false;
         let mut looks_left = false;
@@ -84,11 +228,13 @@ impl<'a> Analyzer<'a> {
             }
             Expr::Any { .. } => {
                 min_size = 1;
+                max_size = Some(1);
                 const_size = true;
             }
             Expr::Literal { ref val, casei } => {
                 // right now each character in a literal gets its own node, that might change
                 min_size = 1;
+                max_size = Some(1);
                 const_size = literal_const_size(val, casei);
             }
             Expr::StartText | Expr::StartLine => {
@@ -101,6 +247,9 @@ impl<'a> Analyzer<'a> {
                     let analyzed_child = self.visit(child)?;
                     looks_left |= analyzed_child.looks_left && min_size == 0;
                     min_size += analyzed_child.min_size;
+                    max_size = max_size.and_then(|total| {
+                        analyzed_child.max_size.map(|child_max| total + child_max)
+                    });
                     const_size &= analyzed_child.const_size;
                     hard |= analyzed_child.hard;
                     children.push(analyzed_child);
@@ -109,6 +258,7 @@ impl<'a> Analyzer<'a> {
             Expr::Alt(ref v) => {
                 let analyzed_child = self.visit(&v[0])?;
                 min_size = analyzed_child.min_size;
+                max_size = analyzed_child.max_size;
                 const_size = analyzed_child.const_size;
                 hard = analyzed_child.hard;
                 children.push(analyzed_child);
@@ -116,6 +266,10 @@ impl<'a> Analyzer<'a> {
                     let analyzed_child = self.visit(child)?;
                     const_size &= analyzed_child.const_size && min_size == analyzed_child.min_size;
                     min_size = min(min_size, analyzed_child.min_size);
+                    max_size = match (max_size, analyzed_child.max_size) {
+                        (Some(a), Some(b)) => Some(a.max(b)),
+                        _ => None,
+                    };
                     hard |= analyzed_child.hard;
                     looks_left |= analyzed_child.looks_left;
                     children.push(analyzed_child);
@@ -126,6 +280,7 @@ impl<'a> Analyzer<'a> {
                 self.group_ix += 1;
                 let analyzed_child = self.visit(child)?;
                 min_size = analyzed_child.min_size;
+                max_size = analyzed_child.max_size;
                 const_size = analyzed_child.const_size;
                 looks_left = analyzed_child.looks_left;
                 hard = analyzed_child.hard | self.backrefs.contains(group);
@@ -133,7 +288,8 @@ impl<'a> Analyzer<'a> {
             }
             Expr::LookAround(ref child, _) => {
                 let analyzed_child = self.visit(child)?;
-                // min_size = 0
+                // min_size = 0, max_size = Some(0): a lookaround doesn't
+                // consume any input itself
                 const_size = true;
                 hard = true;
                 looks_left = analyzed_child.looks_left;
@@ -142,6 +298,14 @@ impl<'a> Analyzer<'a> {
             Expr::Repeat { ref child, lo, hi, .. } => {
                 let analyzed_child = self.visit(child)?;
                 min_size = analyzed_child.min_size * lo;
+                // `hi == usize::MAX` is how an unbounded repeat is
+                // represented (see the `lo == hi` check just below, which
+                // relies on the same convention).
+                max_size = if hi == usize::MAX {
+                    None
+                } else {
+                    analyzed_child.max_size.map(|m| m * hi)
+                };
                 const_size = analyzed_child.const_size && lo == hi;
                 hard = analyzed_child.hard;
                 looks_left = analyzed_child.looks_left;
@@ -150,6 +314,7 @@ impl<'a> Analyzer<'a> {
             Expr::Delegate { size, .. } => {
                 // currently only used for empty and single-char matches
                 min_size = size;
+                max_size = Some(size);
                 const_size = true;
                 looks_left = size == 0;  // TODO: conservative for \z
             }
@@ -157,11 +322,14 @@ impl<'a> Analyzer<'a> {
                 if group >= self.group_ix {
                     return Err(Error::InvalidBackref);
                 }
+                // length depends on what the referenced group captured
+                max_size = None;
                 hard = true;
             }
             Expr::AtomicGroup(ref child) => {
                 let analyzed_child = self.visit(child)?;
                 min_size = analyzed_child.min_size;
+                max_size = analyzed_child.max_size;
                 const_size = analyzed_child.const_size;
                 looks_left = analyzed_child.looks_left;
                 hard = true;  // TODO: possibly could weaken
@@ -175,6 +343,7 @@ impl<'a> Analyzer<'a> {
             start_group: start_group,
             end_group: self.group_ix,
             min_size: min_size,
+            max_size: max_size,
             const_size: const_size,
             hard: hard,
             looks_left: looks_left,
@@ -190,6 +359,81 @@ fn literal_const_size(_: &str, _: bool) -> bool {
     true
 }
 
+/// Returns true if `expr` contains a case-sensitive literal with an
+/// uppercase character, i.e. the pattern expresses deliberate
+/// case-sensitive intent (used for smart-case: a pattern with no uppercase
+/// literal can be searched case-insensitively without surprising the
+/// user). Literals already marked `casei` are skipped, since an enclosing
+/// `(?i)` group already says "don't care about case" for them.
+pub fn has_uppercase_literal(expr: &Expr) -> bool {
+    match *expr {
+        Expr::Literal { ref val, casei } => !casei && val.chars().any(|c| c.is_uppercase()),
+        Expr::Concat(ref v) | Expr::Alt(ref v) => v.iter().any(has_uppercase_literal),
+        Expr::Group(ref child) |
+        Expr::Repeat { ref child, .. } |
+        Expr::LookAround(ref child, _) |
+        Expr::AtomicGroup(ref child) => has_uppercase_literal(child),
+        _ => false,
+    }
+}
+
+/// Returns true if `pattern` contains an explicit `(?i)`/`(?-i)` (or
+/// scoped `(?i:...)`/`(?-i:...)`) flag group. `Expr::Literal` only carries
+/// the resolved `casei` bool, not where it came from, so smart-case can't
+/// tell "explicitly case-sensitive" from "just lowercase" once it's down
+/// in the tree; checking the raw pattern text lets it back off instead of
+/// guessing. Backslash escapes and `[...]` classes are skipped so a
+/// literal `(?i)` or a `[-i)]` class isn't a false positive.
+pub fn has_explicit_case_flag(pattern: &str) -> bool {
+    let bytes = pattern.as_bytes();
+    let mut i = 0;
+    let mut in_class = false;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' => i += 1,
+            b'[' if !in_class => in_class = true,
+            b']' if in_class => in_class = false,
+            b'(' if !in_class && bytes[i..].starts_with(b"(?") => {
+                let mut j = i + 2;
+                // flags are letters and `-`; anything else (`=`, `!`, `<`,
+                // `P<name>`'s `<`) means this isn't a flag group
+                while j < bytes.len() && (bytes[j].is_ascii_alphabetic() || bytes[j] == b'-') {
+                    if bytes[j] == b'i' {
+                        return true;
+                    }
+                    j += 1;
+                }
+                i = j;
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    false
+}
+
+/// Applies smart-case to `expr` in place: marks every literal
+/// case-insensitive, so a lowercase-only pattern like `error` also
+/// matches `Error`/`ERROR`. Call this only when `has_uppercase_literal`
+/// and `has_explicit_case_flag` both returned `false` for the pattern the
+/// tree came from, so a deliberate `(?i)`/`(?-i)` group is never
+/// overridden.
+pub fn apply_smart_case(expr: &mut Expr) {
+    match *expr {
+        Expr::Literal { ref mut casei, .. } => *casei = true,
+        Expr::Concat(ref mut v) | Expr::Alt(ref mut v) => {
+            for child in v.iter_mut() {
+                apply_smart_case(child);
+            }
+        }
+        Expr::Group(ref mut child) |
+        Expr::Repeat { ref mut child, .. } |
+        Expr::LookAround(ref mut child, _) |
+        Expr::AtomicGroup(ref mut child) => apply_smart_case(child),
+        _ => {}
+    }
+}
+
 pub fn analyze<'a>(expr: &'a Expr, backrefs: &'a BitSet) -> Result<AnalyzedExpr<'a>> {
     let mut analyzer = Analyzer {
         backrefs: backrefs,
@@ -206,6 +450,8 @@ mod tests {
     use Expr;
     use super::analyze;
     use super::literal_const_size;
+    use super::has_uppercase_literal;
+    use prefilter::Prefilter;
 
     #[test]
     fn case_folding_safe() {
@@ -252,4 +498,201 @@ mod tests {
         let analyzed_expr = analyze(&e, &backrefs).unwrap();
         assert_eq!(analyzed_expr.is_literal(), false);
     }
+
+    #[test]
+    fn prefix_literals_concat() {
+        let (e, backrefs) = Expr::parse("abc\\d+").unwrap();
+        let analyzed_expr = analyze(&e, &backrefs).unwrap();
+        assert_eq!(analyzed_expr.prefix_literals().literals(), Some(&["abc".to_string()][..]));
+    }
+
+    #[test]
+    fn prefix_literals_alt_union() {
+        let (e, backrefs) = Expr::parse("foo|bar").unwrap();
+        let analyzed_expr = analyze(&e, &backrefs).unwrap();
+        assert_eq!(
+            analyzed_expr.prefix_literals().literals(),
+            Some(&["foo".to_string(), "bar".to_string()][..])
+        );
+    }
+
+    #[test]
+    fn prefix_literals_alt_bails_on_variable_branch() {
+        let (e, backrefs) = Expr::parse("foo|\\d+").unwrap();
+        let analyzed_expr = analyze(&e, &backrefs).unwrap();
+        assert!(analyzed_expr.prefix_literals().is_empty());
+    }
+
+    #[test]
+    fn inner_literal_fallback() {
+        let (e, backrefs) = Expr::parse("\\d+error").unwrap();
+        let analyzed_expr = analyze(&e, &backrefs).unwrap();
+        assert!(analyzed_expr.prefix_literals().is_empty());
+        assert_eq!(analyzed_expr.inner_literal(), Some("error".to_string()));
+    }
+
+    #[test]
+    fn has_uppercase_literal_lowercase_only() {
+        let (e, _) = Expr::parse("error: \\d+").unwrap();
+        assert!(!has_uppercase_literal(&e));
+    }
+
+    #[test]
+    fn has_uppercase_literal_with_uppercase() {
+        let (e, _) = Expr::parse("Error: \\d+").unwrap();
+        assert!(has_uppercase_literal(&e));
+    }
+
+    #[test]
+    fn has_uppercase_literal_ignores_escapes() {
+        let (e, _) = Expr::parse("\\W+").unwrap();
+        assert!(!has_uppercase_literal(&e));
+    }
+
+    #[test]
+    fn has_uppercase_literal_respects_explicit_casei_group() {
+        let (e, _) = Expr::parse("(?i:Error)").unwrap();
+        assert!(!has_uppercase_literal(&e));
+    }
+
+    fn all_literals_casei(expr: &Expr) -> bool {
+        match *expr {
+            Expr::Literal { casei, .. } => casei,
+            Expr::Concat(ref v) | Expr::Alt(ref v) => v.iter().all(all_literals_casei),
+            Expr::Group(ref child) |
+            Expr::Repeat { ref child, .. } |
+            Expr::LookAround(ref child, _) |
+            Expr::AtomicGroup(ref child) => all_literals_casei(child),
+            _ => true,
+        }
+    }
+
+    #[test]
+    fn has_explicit_case_flag_detects_inline_group() {
+        assert!(super::has_explicit_case_flag("(?i)error"));
+    }
+
+    #[test]
+    fn has_explicit_case_flag_detects_scoped_negation() {
+        assert!(super::has_explicit_case_flag("foo(?-i:bar)"));
+    }
+
+    #[test]
+    fn has_explicit_case_flag_absent_by_default() {
+        assert!(!super::has_explicit_case_flag("error: \\d+"));
+    }
+
+    #[test]
+    fn has_explicit_case_flag_ignores_character_class() {
+        assert!(!super::has_explicit_case_flag("[-i)]"));
+    }
+
+    #[test]
+    fn apply_smart_case_marks_literals_casei() {
+        let (mut e, _) = Expr::parse("error").unwrap();
+        assert!(!all_literals_casei(&e));
+        super::apply_smart_case(&mut e);
+        assert!(all_literals_casei(&e));
+    }
+
+    #[test]
+    fn apply_smart_case_reaches_nested_literals() {
+        let (mut e, _) = Expr::parse("(foo|bar)+baz").unwrap();
+        super::apply_smart_case(&mut e);
+        assert!(all_literals_casei(&e));
+    }
+
+    #[test]
+    fn max_size_concat() {
+        let (e, backrefs) = Expr::parse("ab").unwrap();
+        let analyzed_expr = analyze(&e, &backrefs).unwrap();
+        assert_eq!(analyzed_expr.max_size, Some(2));
+    }
+
+    #[test]
+    fn max_size_bounded_repeat() {
+        let (e, backrefs) = Expr::parse("a{2,4}").unwrap();
+        let analyzed_expr = analyze(&e, &backrefs).unwrap();
+        assert_eq!(analyzed_expr.max_size, Some(4));
+    }
+
+    #[test]
+    fn max_size_unbounded_repeat() {
+        let (e, backrefs) = Expr::parse("a+").unwrap();
+        let analyzed_expr = analyze(&e, &backrefs).unwrap();
+        assert_eq!(analyzed_expr.max_size, None);
+    }
+
+    #[test]
+    fn max_size_alt_takes_largest_branch() {
+        let (e, backrefs) = Expr::parse("a|bbb").unwrap();
+        let analyzed_expr = analyze(&e, &backrefs).unwrap();
+        assert_eq!(analyzed_expr.max_size, Some(3));
+    }
+
+    #[test]
+    fn max_size_backref_is_unbounded() {
+        let (e, backrefs) = Expr::parse("(a+)\\1").unwrap();
+        let analyzed_expr = analyze(&e, &backrefs).unwrap();
+        assert_eq!(analyzed_expr.max_size, None);
+    }
+
+    #[test]
+    fn max_size_lookaround_is_zero() {
+        let (e, backrefs) = Expr::parse("(?=abc)").unwrap();
+        let analyzed_expr = analyze(&e, &backrefs).unwrap();
+        assert_eq!(analyzed_expr.max_size, Some(0));
+    }
+
+    #[test]
+    fn suffix_literals_concat() {
+        let (e, backrefs) = Expr::parse("\\d+error").unwrap();
+        let analyzed_expr = analyze(&e, &backrefs).unwrap();
+        assert_eq!(analyzed_expr.suffix_literals().literals(), Some(&["error".to_string()][..]));
+    }
+
+    #[test]
+    fn suffix_literals_alt_union() {
+        let (e, backrefs) = Expr::parse(".*foo|.*bar").unwrap();
+        let analyzed_expr = analyze(&e, &backrefs).unwrap();
+        assert_eq!(
+            analyzed_expr.suffix_literals().literals(),
+            Some(&["foo".to_string(), "bar".to_string()][..])
+        );
+    }
+
+    #[test]
+    fn suffix_literals_empty_when_tail_is_not_literal() {
+        let (e, backrefs) = Expr::parse("error\\d+").unwrap();
+        let analyzed_expr = analyze(&e, &backrefs).unwrap();
+        assert!(analyzed_expr.suffix_literals().is_empty());
+    }
+
+    #[test]
+    fn build_reverse_prefilter_ignores_hardness_outside_the_tail() {
+        let (e, backrefs) = Expr::parse("(a)\\1end").unwrap();
+        let analyzed_expr = analyze(&e, &backrefs).unwrap();
+        // the backref makes the whole expression hard, but "end" is still
+        // a clean literal suffix, so a reverse prefilter can be built
+        assert!(analyzed_expr.hard);
+        let is_none = match analyzed_expr.build_reverse_prefilter() {
+            Prefilter::None => true,
+            _ => false,
+        };
+        assert!(!is_none);
+    }
+
+    #[test]
+    fn build_reverse_prefilter_survives_a_leading_lookaround() {
+        let (e, backrefs) = Expr::parse("(?=x).*bar").unwrap();
+        let analyzed_expr = analyze(&e, &backrefs).unwrap();
+        // a leading lookaround makes the whole expression hard, but the
+        // trailing "bar" is untouched by it
+        assert!(analyzed_expr.hard);
+        let is_none = match analyzed_expr.build_reverse_prefilter() {
+            Prefilter::None => true,
+            _ => false,
+        };
+        assert!(!is_none);
+    }
 }
@@ -0,0 +1,113 @@
+// Copyright 2016 Google Inc. All rights reserved.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! Literal sets computed from the analyzed expression tree, used to build
+//! search prefilters (see `prefilter.rs`).
+
+/// Literals shorter than this aren't worth prefiltering on. An empty
+/// literal matches everywhere, so it's dropped; a single byte is still
+/// useful (it compiles down to a `memchr` scan in `Prefilter`).
+const MIN_LITERAL_LEN: usize = 1;
+
+/// Literal sets larger than this stop paying for themselves; an
+/// Aho-Corasick automaton over too many alternatives degrades towards a
+/// full scan anyway.
+const MAX_LITERAL_SET_SIZE: usize = 8;
+
+/// A set of literal strings known to be required by a match, or an empty
+/// set if no useful literal could be determined.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LiteralSet {
+    literals: Option<Vec<String>>,
+}
+
+impl LiteralSet {
+    /// A set with no known literals.
+    pub fn empty() -> LiteralSet {
+        LiteralSet { literals: None }
+    }
+
+    /// A set containing just the one literal, dropped if it's too short to
+    /// be worth prefiltering on.
+    pub fn single(lit: String) -> LiteralSet {
+        if lit.len() < MIN_LITERAL_LEN {
+            LiteralSet::empty()
+        } else {
+            LiteralSet { literals: Some(vec![lit]) }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.literals.is_none()
+    }
+
+    pub fn literals(&self) -> Option<&[String]> {
+        self.literals.as_ref().map(|v| v.as_slice())
+    }
+
+    /// Unions another set into this one, as used for `Alt`: the combined
+    /// set is only useful if every branch contributed a literal, and the
+    /// result stays under the size cap.
+    pub fn union(self, other: LiteralSet) -> LiteralSet {
+        match (self.literals, other.literals) {
+            (Some(mut a), Some(b)) => {
+                a.extend(b);
+                if a.len() > MAX_LITERAL_SET_SIZE {
+                    LiteralSet::empty()
+                } else {
+                    LiteralSet { literals: Some(a) }
+                }
+            }
+            _ => LiteralSet::empty(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LiteralSet, MAX_LITERAL_SET_SIZE};
+
+    #[test]
+    fn single_drops_empty_literal() {
+        assert!(LiteralSet::single("".to_string()).is_empty());
+    }
+
+    #[test]
+    fn single_keeps_one_byte_literal() {
+        // a single byte still makes a useful `memchr`-based prefilter
+        assert!(!LiteralSet::single("a".to_string()).is_empty());
+    }
+
+    #[test]
+    fn union_requires_both_sides() {
+        let a = LiteralSet::single("foo".to_string());
+        let b = LiteralSet::empty();
+        assert!(a.union(b).is_empty());
+    }
+
+    #[test]
+    fn union_caps_set_size() {
+        let mut set = LiteralSet::single("aa".to_string());
+        for _ in 0..MAX_LITERAL_SET_SIZE {
+            set = set.union(LiteralSet::single("bb".to_string()));
+        }
+        assert!(set.is_empty());
+    }
+}
@@ -0,0 +1,99 @@
+// Copyright 2016 Google Inc. All rights reserved.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! Turns a `LiteralSet` computed by the analyzer into a fast scan that a
+//! search loop can use to jump straight to the next candidate position
+//! instead of retrying the full backtracking engine at every offset.
+//! `prev_candidate` is the reverse counterpart, for an end-anchored search
+//! driven by a required trailing literal (patterns like `.*ERROR`).
+
+use aho_corasick::{AhoCorasick, AhoCorasickBuilder, MatchKind};
+use memchr::{memchr, memrchr};
+
+use literal::LiteralSet;
+
+/// A compiled prefilter, or `None` if the pattern didn't yield a useful
+/// literal set and every offset has to be tried.
+pub enum Prefilter {
+    /// No usable literal set.
+    None,
+    /// A single leading byte, scanned with `memchr`.
+    Byte(u8),
+    /// Any other literal set: several literals, or one longer than a
+    /// byte. Forward scans use the Aho-Corasick
+    /// automaton; `prev_candidate` walks the literals directly (see its
+    /// doc comment for why).
+    Multi(AhoCorasick, Vec<String>),
+}
+
+impl Prefilter {
+    /// Builds a prefilter from a literal set computed by the analyzer.
+    pub fn from_literal_set(set: &LiteralSet) -> Prefilter {
+        let literals = match set.literals() {
+            Some(lits) if !lits.is_empty() => lits,
+            _ => return Prefilter::None,
+        };
+        if literals.len() == 1 && literals[0].len() == 1 {
+            return Prefilter::Byte(literals[0].as_bytes()[0]);
+        }
+        // `build` returns a `Result` in current `aho-corasick` versions;
+        // it only errs on pattern-count/automaton-size limits we're nowhere
+        // near here, given `MAX_LITERAL_SET_SIZE` caps the input.
+        // Leftmost-first, so `find` reports the earliest-starting literal
+        // rather than the earliest-ending one.
+        let automaton = AhoCorasickBuilder::new()
+            .match_kind(MatchKind::LeftmostFirst)
+            .build(literals)
+            .expect("capped literal set should always build");
+        Prefilter::Multi(automaton, literals.to_vec())
+    }
+
+    /// Finds the next offset at or after `start` where a candidate match
+    /// could begin, or `None` if there isn't one left in `text`.
+    pub fn next_candidate(&self, text: &str, start: usize) -> Option<usize> {
+        match *self {
+            Prefilter::None => Some(start),
+            Prefilter::Byte(b) => memchr(b, &text.as_bytes()[start..]).map(|i| start + i),
+            Prefilter::Multi(ref ac, _) => ac.find(&text[start..]).map(|m| start + m.start()),
+        }
+    }
+
+    /// Finds the rightmost occurrence at or before `end_before`, searching
+    /// backwards from there. Returns the offset right after the match, to
+    /// use as the end anchor for an end-anchored match attempt. The `Multi`
+    /// case walks candidate end offsets down from `end_before` directly
+    /// against the literal list, rather than running the forward
+    /// Aho-Corasick automaton, which would scan the wrong direction.
+    pub fn prev_candidate(&self, text: &str, end_before: usize) -> Option<usize> {
+        match *self {
+            Prefilter::None => Some(end_before),
+            Prefilter::Byte(b) => memrchr(b, &text.as_bytes()[..end_before]).map(|i| i + 1),
+            Prefilter::Multi(_, ref literals) => {
+                let bytes = text.as_bytes();
+                (0..=end_before).rev().find(|&end| {
+                    literals.iter().any(|lit| {
+                        let lit = lit.as_bytes();
+                        end >= lit.len() && &bytes[end - lit.len()..end] == lit
+                    })
+                })
+            }
+        }
+    }
+}